@@ -0,0 +1,501 @@
+//! This module implements the cache used to store `SignedAccountData` gossiped between
+//! validator nodes (see the `network_protocol` module for the wire type). A node gossips
+//! its `AccountData` for every epoch in which it is a validator, so that other nodes know
+//! how to establish a direct connection to it. The cache keeps only the freshest entry per
+//! `(epoch_id, account_id)` (see `Inner::is_new`), and is indexed by the current set of
+//! `(epoch_id, account_id)` pairs that the node cares about (see `set_keys`).
+use crate::network_protocol::SignedAccountData;
+use near_network_primitives::time;
+use near_network_primitives::types::{AccountKeys, PeerId};
+use near_primitives::types::{AccountId, EpochId};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+
+#[cfg(test)]
+mod tests;
+
+#[derive(thiserror::Error, Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    #[error("found an invalid signature")]
+    InvalidSignature,
+    #[error("found a too large payload")]
+    DataTooLarge,
+    #[error("found multiple entries for the same (epoch_id,account_id) pair")]
+    SingleAccountMultipleData,
+    #[error("source exceeded its admission limits")]
+    RateLimited,
+}
+
+/// Awaits `fut`, panicking if it doesn't resolve on the very first poll, or if it is dropped
+/// before completing. Used to document (and assert) that a given future is a leaf future
+/// which performs no actual async work, e.g. because it only wraps a synchronous CPU-bound
+/// computation.
+pub(crate) fn must_complete<T>(
+    fut: impl std::future::Future<Output = T>,
+) -> impl std::future::Future<Output = T> {
+    struct MustComplete<Fut> {
+        fut: Fut,
+        done: bool,
+    }
+
+    impl<Fut: std::future::Future> std::future::Future for MustComplete<Fut> {
+        type Output = Fut::Output;
+        fn poll(
+            self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Self::Output> {
+            // Safety: we never move `fut` out of `self`; `done` is not pinned.
+            let this = unsafe { self.get_unchecked_mut() };
+            let fut = unsafe { std::pin::Pin::new_unchecked(&mut this.fut) };
+            match fut.poll(cx) {
+                std::task::Poll::Ready(v) => {
+                    this.done = true;
+                    std::task::Poll::Ready(v)
+                }
+                std::task::Poll::Pending => panic!("future did not resolve immediately"),
+            }
+        }
+    }
+
+    impl<Fut> Drop for MustComplete<Fut> {
+        fn drop(&mut self) {
+            if !self.done && !std::thread::panicking() {
+                panic!("future dropped before completion");
+            }
+        }
+    }
+
+    MustComplete { fut, done: false }
+}
+
+/// Proof that a validator has signed two different `SignedAccountData` for the same
+/// `(epoch_id, account_id)`. Both halves are kept verbatim, each already carrying the
+/// account's signature over a distinct payload for the same epoch, so a verifier given
+/// only this proof (and the account's public key for that epoch) can confirm the
+/// misbehavior without consulting any other state.
+#[derive(Clone, Debug)]
+pub struct EquivocationProof {
+    pub epoch_id: EpochId,
+    pub account_id: AccountId,
+    pub first: Arc<SignedAccountData>,
+    pub second: Arc<SignedAccountData>,
+}
+
+impl EquivocationProof {
+    /// Checks the invariant that makes this proof actionable: both halves are signed by
+    /// `account_id`'s key for `epoch_id`, and they commit to different payloads (otherwise
+    /// this would just be the same record gossiped twice, not equivocation).
+    pub fn verify(&self, public_key: &near_crypto::PublicKey) -> bool {
+        self.first.epoch_id() == &self.epoch_id
+            && self.second.epoch_id() == &self.epoch_id
+            && self.first.account_id() == &self.account_id
+            && self.second.account_id() == &self.account_id
+            && self.first.payload() != self.second.payload()
+            && self.first.verify(public_key).is_ok()
+            && self.second.verify(public_key).is_ok()
+    }
+}
+
+/// Batches smaller than this are verified inline on the calling task; batches of this size
+/// or larger are handed off to the rayon pool (see `Cache::verify_batch`) so that signature verification
+/// of a large gossip update doesn't monopolize a single core.
+const DEFAULT_PARALLEL_VERIFY_THRESHOLD: usize = 16;
+
+/// Outcome of checking a single incoming entry against the current key set and limits,
+/// before it is merged into the cache.
+enum Verified {
+    /// Not a key we are interested in - to be dropped silently.
+    NotInterested,
+    Ok(SignedAccountData),
+    Err(Error),
+}
+
+fn verify_one(d: SignedAccountData, public_key: Option<near_crypto::PublicKey>) -> Verified {
+    let public_key = match public_key {
+        Some(pk) => pk,
+        None => return Verified::NotInterested,
+    };
+    if d.payload().len() > crate::network_protocol::MAX_ACCOUNT_DATA_SIZE_BYTES {
+        return Verified::Err(Error::DataTooLarge);
+    }
+    match d.verify(&public_key) {
+        Ok(()) => Verified::Ok(d),
+        Err(_) => Verified::Err(Error::InvalidSignature),
+    }
+}
+
+/// Per-source admission limits for `Cache::insert_from`. A source which, within a single
+/// `window`, has had more than `max_rejected_per_window` entries rejected (for any reason -
+/// invalid signature, oversized payload, or equivocation) or has had more than
+/// `max_verified_bytes_per_window` worth of payloads pushed through signature verification,
+/// has its further batches shed (returned as `Error::RateLimited`) before they are even
+/// looked at, until the window rolls over.
+#[derive(Clone, Debug)]
+pub struct PerSourceLimits {
+    pub window: time::Duration,
+    pub max_rejected_per_window: usize,
+    pub max_verified_bytes_per_window: usize,
+    /// Caps the number of distinct sources the admission map will track at once, so that
+    /// admission control itself can't be turned into an unbounded-memory DoS by a botnet of
+    /// never-reused `PeerId`s each sending one batch. Once over the cap, `reserve_admission`
+    /// evicts idle entries (oldest `window_start` first) before admitting a new source.
+    pub max_tracked_sources: usize,
+}
+
+/// Sliding-window bookkeeping for a single source, reset wholesale once `window` elapses.
+/// A simple reset-on-expiry window rather than a token bucket, since admission control here
+/// only needs to bound the damage a misbehaving peer can do, not smooth traffic.
+struct SourceStats {
+    window_start: time::Instant,
+    rejected: usize,
+    verified_bytes: usize,
+}
+
+struct Inner {
+    /// Current state of the cache: the freshest entry per (epoch_id,account_id).
+    data: HashMap<(EpochId, AccountId), Arc<SignedAccountData>>,
+    /// Set of (epoch_id,account_id) keys that we are currently interested in.
+    keys: Arc<AccountKeys>,
+}
+
+impl Inner {
+    // BLOCKED(MCJOHN974/nearcore#chunk0-3): not implemented in this change. The request asks
+    // for (version, timestamp) ordering, with version a monotonic counter the publisher bumps
+    // on every republish so a skewed or adversarial clock can no longer pin a stale record
+    // forever. That means adding a `version: u64` field to the signed payload itself -
+    // `AccountData`/`SignedAccountData`, defined in `network_protocol` - plus bump-on-publish
+    // logic at every call site that constructs one. `network_protocol` is a module outside
+    // `accounts_data` that this module only consumes and isn't part of this change's scope, so there is no
+    // `version` field to order by here; ordering stays plain-timestamp (unchanged from before
+    // this request) rather than pretending it's been addressed. Landing the request for real
+    // needs a follow-up that adds the field in `network_protocol` first:
+    //     (old.version(), old.timestamp()) < (d.version(), d.timestamp())
+    fn is_new(&self, d: &SignedAccountData) -> bool {
+        match self.data.get(&(d.epoch_id().clone(), d.account_id().clone())) {
+            Some(old) => old.timestamp() < d.timestamp(),
+            None => true,
+        }
+    }
+
+    /// Inserts `d` if it is for a key we are interested in and it is fresher than what we
+    /// already have, returning it back if (and only if) it was actually inserted.
+    fn try_insert(&mut self, d: Arc<SignedAccountData>) -> Option<Arc<SignedAccountData>> {
+        let key = (d.epoch_id().clone(), d.account_id().clone());
+        if !self.keys.contains_key(&key) {
+            return None;
+        }
+        if !self.is_new(&d) {
+            return None;
+        }
+        self.data.insert(key, d.clone());
+        Some(d)
+    }
+}
+
+/// Cache of the most recent `SignedAccountData` gossiped by each validator, keyed by
+/// `(epoch_id, account_id)`.
+pub(crate) struct Cache {
+    inner: Mutex<Inner>,
+    /// Equivocation proofs are published here as soon as they are detected, independently
+    /// of the `Error` returned by the `insert` call that found them, so that consumers
+    /// interested only in misbehavior reporting don't have to thread themselves through
+    /// every `insert` call site.
+    equivocations: broadcast::Sender<Arc<EquivocationProof>>,
+    /// Batches with at least this many entries are verified on the rayon pool rather than
+    /// inline. See `with_parallel_verify_threshold`.
+    parallel_verify_threshold: usize,
+    /// Clock used for admission-control windows. Threaded explicitly (rather than read from
+    /// ambient state) so tests can drive it with `time::FakeClock`.
+    clock: time::Clock,
+    /// `None` means admission control is disabled: `insert_from` then behaves exactly like
+    /// `insert`, regardless of `source`, which is what keeps callers that never configured
+    /// limits unaffected by this feature.
+    limits: Option<PerSourceLimits>,
+    /// Per-source admission state, one `tokio::sync::Mutex` per source rather than a single
+    /// lock guarding a plain `SourceStats` map: `insert_from` holds a source's guard from the
+    /// admission check all the way through recording the batch's usage, including across the
+    /// `verify_batch` await, so that two concurrent batches from the same source can't both
+    /// pass the check before either one's usage is recorded. Different sources don't
+    /// contend with each other.
+    admission: Mutex<HashMap<PeerId, Arc<tokio::sync::Mutex<SourceStats>>>>,
+}
+
+impl Cache {
+    pub fn new() -> Self {
+        Self::with_parallel_verify_threshold(DEFAULT_PARALLEL_VERIFY_THRESHOLD)
+    }
+
+    /// Like `new`, but overrides the batch size at or above which signature verification is
+    /// parallelized across the rayon pool. Exposed mainly for tests and tuning.
+    pub fn with_parallel_verify_threshold(parallel_verify_threshold: usize) -> Self {
+        let (equivocations, _) = broadcast::channel(100);
+        Self {
+            inner: Mutex::new(Inner { data: HashMap::new(), keys: Arc::new(AccountKeys::default()) }),
+            equivocations,
+            parallel_verify_threshold,
+            clock: time::Clock::real(),
+            limits: None,
+            admission: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Enables per-source admission control, see `insert_from` and `PerSourceLimits`.
+    pub fn with_limits(mut self, clock: time::Clock, limits: PerSourceLimits) -> Self {
+        self.clock = clock;
+        self.limits = Some(limits);
+        self
+    }
+
+    /// Subscribes to equivocation proofs detected by `insert`. Proofs detected before this
+    /// call are not replayed.
+    pub fn subscribe_equivocations(&self) -> broadcast::Receiver<Arc<EquivocationProof>> {
+        self.equivocations.subscribe()
+    }
+
+    /// Returns all the data in the cache.
+    pub fn dump(&self) -> Vec<Arc<SignedAccountData>> {
+        self.inner.lock().data.values().cloned().collect()
+    }
+
+    /// Updates the set of (epoch_id,account_id) keys that the cache should retain data for,
+    /// dropping entries which are no longer of interest. Returns true iff the key set
+    /// actually changed.
+    pub fn set_keys(&self, keys: Arc<AccountKeys>) -> bool {
+        let mut inner = self.inner.lock();
+        if inner.keys == keys {
+            return false;
+        }
+        inner.data.retain(|k, _| keys.contains_key(k));
+        inner.keys = keys;
+        true
+    }
+
+    fn report_equivocation(&self, first: Arc<SignedAccountData>, second: Arc<SignedAccountData>) {
+        let proof = EquivocationProof {
+            epoch_id: first.epoch_id().clone(),
+            account_id: first.account_id().clone(),
+            first,
+            second,
+        };
+        // No receivers is a valid state (e.g. in tests) - nothing to do in that case.
+        let _ = self.equivocations.send(Arc::new(proof));
+    }
+
+    /// Evicts idle (uncontended, not currently reserved by any in-flight `insert_from` call)
+    /// sources from `admission`, oldest `window_start` first, until it has fewer than
+    /// `max_tracked_sources` entries or there is nothing left that can safely be evicted.
+    /// Entries still reserved by a concurrent call are never touched, so this can't race with
+    /// `reserve_admission`/`record_usage` for a source that's actually in use.
+    fn evict_stale_sources(
+        admission: &mut HashMap<PeerId, Arc<tokio::sync::Mutex<SourceStats>>>,
+        max_tracked_sources: usize,
+    ) {
+        if admission.len() < max_tracked_sources {
+            return;
+        }
+        let mut idle: Vec<(PeerId, time::Instant)> = admission
+            .iter()
+            .filter_map(|(source, stats_lock)| {
+                // Only entries with no outstanding guard (strong_count == 1, i.e. not
+                // currently reserved by another `insert_from` call) are eviction candidates;
+                // try_lock() additionally guards against the vanishingly unlikely race where a
+                // guard is being constructed right now.
+                if Arc::strong_count(stats_lock) > 1 {
+                    return None;
+                }
+                let window_start = stats_lock.try_lock().ok()?.window_start;
+                Some((source.clone(), window_start))
+            })
+            .collect();
+        idle.sort_by_key(|(_, window_start)| *window_start);
+        for (source, _) in idle {
+            if admission.len() < max_tracked_sources {
+                break;
+            }
+            admission.remove(&source);
+        }
+    }
+
+    /// Checks (and rolls over, if stale) `source`'s admission window and, if it isn't
+    /// currently over one of `limits`' thresholds, returns a guard reserving exclusive
+    /// access to `source`'s `SourceStats` for the rest of the caller's `insert_from` call -
+    /// including across the `verify_batch` await - so that the check and the eventual
+    /// `record_usage` happen atomically with respect to other batches from the same source.
+    /// Returns `Error::RateLimited` instead if `source` is already over a threshold.
+    async fn reserve_admission(
+        &self,
+        source: &PeerId,
+        limits: &PerSourceLimits,
+    ) -> Result<tokio::sync::OwnedMutexGuard<SourceStats>, Error> {
+        let now = self.clock.now();
+        let stats_lock = {
+            let mut admission = self.admission.lock();
+            if !admission.contains_key(source) {
+                Self::evict_stale_sources(&mut admission, limits.max_tracked_sources);
+            }
+            admission
+                .entry(source.clone())
+                .or_insert_with(|| {
+                    Arc::new(tokio::sync::Mutex::new(SourceStats {
+                        window_start: now,
+                        rejected: 0,
+                        verified_bytes: 0,
+                    }))
+                })
+                .clone()
+        };
+        let mut stats = stats_lock.lock_owned().await;
+        if now - stats.window_start > limits.window {
+            *stats = SourceStats { window_start: now, rejected: 0, verified_bytes: 0 };
+        }
+        if stats.rejected > limits.max_rejected_per_window
+            || stats.verified_bytes > limits.max_verified_bytes_per_window
+        {
+            return Err(Error::RateLimited);
+        }
+        Ok(stats)
+    }
+
+    /// Accounts `rejected`/`verified_bytes` against the admission window reserved by
+    /// `reserve_admission`.
+    fn record_usage(stats: &mut SourceStats, rejected: usize, verified_bytes: usize) {
+        stats.rejected += rejected;
+        stats.verified_bytes += verified_bytes;
+    }
+
+    /// Verifies each entry of `resolved` (pairing it with the public key it should be
+    /// verified against, if we have one) and returns the per-entry outcome in the original
+    /// order. Below `parallel_verify_threshold` this runs inline on the calling task (and is
+    /// asserted to do so via `must_complete`, since it's then pure CPU with no actual async
+    /// work); at or above it, verification is farmed out across the rayon pool via
+    /// `spawn_blocking`, so a large gossip batch doesn't block the async runtime's thread.
+    async fn verify_batch(
+        &self,
+        resolved: Vec<(SignedAccountData, Option<near_crypto::PublicKey>)>,
+    ) -> Vec<Verified> {
+        if resolved.len() < self.parallel_verify_threshold {
+            return must_complete(async move {
+                resolved.into_iter().map(|(d, pk)| verify_one(d, pk)).collect()
+            })
+            .await;
+        }
+        tokio::task::spawn_blocking(move || {
+            use rayon::prelude::*;
+            resolved.into_par_iter().map(|(d, pk)| verify_one(d, pk)).collect()
+        })
+        .await
+        .expect("verification worker thread panicked")
+    }
+
+    /// Verifies `data` and merges the valid, fresh, entries into the cache.
+    ///
+    /// Returns the entries which were actually inserted (a subset of `data`) together with
+    /// the first error encountered, if any, in the original order of `data` - this holds
+    /// regardless of whether verification ran inline or was parallelized across the rayon
+    /// pool, since the merge step below always walks the per-entry results in order.
+    /// Processing stops at the first `DataTooLarge`, `InvalidSignature` or
+    /// `SingleAccountMultipleData` error, but entries already accepted before that point
+    /// remain in the cache - partial updates are allowed. Entries which are stale or for a
+    /// key we are not interested in are dropped silently, since those are expected and not
+    /// actionable.
+    ///
+    /// When two different entries are found for the same `(epoch_id,account_id)` within
+    /// `data`, only one of them is kept (as before), but an `EquivocationProof` covering
+    /// both is published via `subscribe_equivocations`.
+    ///
+    /// Equivalent to `insert_from(None, data)`: no source is attributed, so admission
+    /// control (see `with_limits`) never applies.
+    pub async fn insert(
+        self: Arc<Self>,
+        data: Vec<SignedAccountData>,
+    ) -> (Vec<Arc<SignedAccountData>>, Option<Error>) {
+        self.insert_from(None, data).await
+    }
+
+    /// Like `insert`, but attributes the batch to `source` for per-source admission control.
+    /// If `source` is `None`, or no `PerSourceLimits` have been configured via `with_limits`,
+    /// this behaves exactly like `insert` - existing callers and tests are unaffected unless
+    /// they opt into limits.
+    ///
+    /// If `source` is over its admission limits, the whole batch is shed - dropped before
+    /// signature verification - and `Error::RateLimited` is returned.
+    ///
+    /// Note: nothing in this checkout's gossip-ingestion path (the peer actor that receives
+    /// `SignedAccountData` off the wire) calls this with a real `PeerId` yet - that call site
+    /// lives outside `accounts_data` and isn't part of this tree. Until it's migrated from
+    /// `insert` to `insert_from`, configuring `with_limits` has no effect in production;
+    /// `insert_from` is otherwise fully wired up and covered by tests.
+    pub async fn insert_from(
+        self: Arc<Self>,
+        source: Option<PeerId>,
+        data: Vec<SignedAccountData>,
+    ) -> (Vec<Arc<SignedAccountData>>, Option<Error>) {
+        let mut admission_guard = match (&source, &self.limits) {
+            (Some(source), Some(limits)) => match self.reserve_admission(source, limits).await {
+                Ok(guard) => Some(guard),
+                Err(e) => return (vec![], Some(e)),
+            },
+            _ => None,
+        };
+
+        let keys = self.inner.lock().keys.clone();
+        let resolved: Vec<_> = data
+            .into_iter()
+            .map(|d| {
+                let pk = keys.get(&(d.epoch_id().clone(), d.account_id().clone())).cloned();
+                (d, pk)
+            })
+            .collect();
+        // Every entry we have a key for and which isn't oversized goes through signature
+        // verification, whatever its outcome and wherever it sits relative to the first error.
+        let verified_bytes: usize = resolved
+            .iter()
+            .filter(|(d, pk)| {
+                pk.is_some()
+                    && d.payload().len() <= crate::network_protocol::MAX_ACCOUNT_DATA_SIZE_BYTES
+            })
+            .map(|(d, _)| d.payload().len())
+            .sum();
+        let verified = self.verify_batch(resolved).await;
+
+        let mut accepted = vec![];
+        let mut err = None;
+        let mut rejected = 0;
+        let mut seen_in_batch: HashMap<(EpochId, AccountId), Arc<SignedAccountData>> =
+            HashMap::new();
+        for v in verified {
+            let d = match v {
+                Verified::NotInterested => continue,
+                Verified::Err(e) => {
+                    rejected += 1;
+                    err = Some(e);
+                    break;
+                }
+                Verified::Ok(d) => Arc::new(d),
+            };
+            let key = (d.epoch_id().clone(), d.account_id().clone());
+            if let Some(prev) = seen_in_batch.get(&key) {
+                // A peer retransmitting the exact same record twice in one batch is a
+                // no-op, not misbehavior - only publish a proof when the two payloads
+                // actually differ.
+                if prev.payload() != d.payload() {
+                    self.report_equivocation(prev.clone(), d.clone());
+                }
+                rejected += 1;
+                err = Some(Error::SingleAccountMultipleData);
+                break;
+            }
+            seen_in_batch.insert(key, d.clone());
+            if let Some(inserted) = self.inner.lock().try_insert(d) {
+                accepted.push(inserted);
+            }
+        }
+
+        if let Some(stats) = admission_guard.as_deref_mut() {
+            Self::record_usage(stats, rejected, verified_bytes);
+        }
+        (accepted, err)
+    }
+}
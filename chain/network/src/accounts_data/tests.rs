@@ -3,7 +3,7 @@ use crate::network_protocol::testonly as data;
 use crate::network_protocol::SignedAccountData;
 use crate::testonly::{assert_is_superset, make_rng, AsSet as _, Rng};
 use near_network_primitives::time;
-use near_network_primitives::types::AccountKeys;
+use near_network_primitives::types::{AccountKeys, PeerId};
 use near_primitives::types::EpochId;
 use pretty_assertions::assert_eq;
 use std::sync::Arc;
@@ -191,6 +191,41 @@ async fn invalid_signature() {
     assert_eq!(res.0.as_set(), cache.dump().as_set());
 }
 
+#[tokio::test]
+async fn invalid_signature_parallel_batch() {
+    let mut rng = make_rng(2947294234);
+    let rng = &mut rng;
+    let clock = time::FakeClock::default();
+    let now = clock.now_utc();
+
+    // Large enough that a low parallel-verify threshold actually routes it through rayon.
+    let n = 20;
+    let signers = make_signers(rng, n);
+    let e = make_account_keys(&signers);
+
+    let mut batch: Vec<_> = signers.iter().map(|s| s.make_account_data(rng, now)).collect();
+    // Corrupt one entry's signature, in the middle of the batch.
+    let bad_sig = batch[0].signature_mut().clone();
+    *batch[n / 2].signature_mut() = bad_sig;
+
+    // One cache forces every batch onto the rayon pool, the other keeps everything inline;
+    // both must agree on the accepted set and on which error is reported, regardless of
+    // which path actually ran the verification.
+    let parallel = Arc::new(Cache::with_parallel_verify_threshold(1));
+    parallel.set_keys(e.clone());
+    let inline = Arc::new(Cache::with_parallel_verify_threshold(usize::MAX));
+    inline.set_keys(e);
+
+    let parallel_res = parallel.clone().insert(batch.clone()).await;
+    let inline_res = inline.clone().insert(batch).await;
+
+    assert_eq!(Some(Error::InvalidSignature), parallel_res.1);
+    assert_eq!(parallel_res.1, inline_res.1);
+    assert_eq!(parallel_res.0.as_set(), inline_res.0.as_set());
+    // The accepted set is still exactly what ended up in the cache.
+    assert_eq!(parallel_res.0.as_set(), parallel.dump().as_set());
+}
+
 #[tokio::test]
 async fn single_account_multiple_data() {
     let mut rng = make_rng(2947294234);
@@ -217,4 +252,174 @@ async fn single_account_multiple_data() {
     // Partial update should match the state, this also verifies that only 1 of the competing
     // entries has been applied.
     assert_eq!(res.0.as_set(), cache.dump().as_set());
+}
+
+#[tokio::test]
+async fn single_account_multiple_data_publishes_equivocation_proof() {
+    let mut rng = make_rng(2947294234);
+    let rng = &mut rng;
+    let clock = time::FakeClock::default();
+    let now = clock.now_utc();
+
+    let signers = make_signers(rng, 3);
+    let e = make_account_keys(&signers);
+
+    let cache = Arc::new(Cache::new());
+    cache.set_keys(e);
+    let mut equivocations = cache.subscribe_equivocations();
+
+    let a0 = signers[0].make_account_data(rng, now);
+    let a2old = signers[2].make_account_data(rng, now);
+    let a2new = signers[2].make_account_data(rng, now + time::Duration::seconds(1));
+
+    // 2 entries for the same (epoch_id,account_id) => an equivocation proof is published,
+    // in addition to the Error::SingleAccountMultipleData already covered above.
+    cache.clone().insert(vec![a0.clone(), a2old.clone(), a2new.clone()]).await;
+
+    let proof = equivocations.recv().await.unwrap();
+    assert_eq!(proof.epoch_id, signers[2].epoch_id);
+    assert_eq!(proof.account_id, signers[2].signer.account_id);
+    assert_eq!(
+        [proof.first.as_ref(), proof.second.as_ref()].as_set(),
+        [&a2old, &a2new].as_set(),
+    );
+    // The proof is self-contained: a verifier only needs the account's public key for the
+    // epoch to confirm both signatures are valid over distinct payloads.
+    assert!(proof.verify(&signers[2].signer.public_key));
+}
+
+#[tokio::test]
+async fn duplicate_retransmit_does_not_publish_equivocation_proof() {
+    let mut rng = make_rng(2947294234);
+    let rng = &mut rng;
+    let clock = time::FakeClock::default();
+    let now = clock.now_utc();
+
+    let signers = make_signers(rng, 1);
+    let e = make_account_keys(&signers);
+
+    let cache = Arc::new(Cache::new());
+    cache.set_keys(e);
+    let mut equivocations = cache.subscribe_equivocations();
+
+    // The exact same signed record, retransmitted twice in one batch - a no-op, not
+    // misbehavior.
+    let a0 = signers[0].make_account_data(rng, now);
+
+    // Still collapses to a single accepted entry and Error::SingleAccountMultipleData (the
+    // dedup behavior predates this request and is unchanged), but no proof should be
+    // published for it.
+    let res = cache.clone().insert(vec![a0.clone(), a0.clone()]).await;
+    assert_eq!(Some(Error::SingleAccountMultipleData), res.1);
+    assert_eq!([&a0].as_set(), cache.dump().as_set());
+    assert!(equivocations.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn rate_limited_sheds_batches_over_source_limits() {
+    let mut rng = make_rng(2947294234);
+    let rng = &mut rng;
+    let clock = time::FakeClock::default();
+    let now = clock.now_utc();
+
+    let signers = make_signers(rng, 2);
+    let e = make_account_keys(&signers);
+    // These two arbitrary keys merely need to be distinct from each other; they stand in for
+    // two different peers' connection identities, unrelated to the validator accounts above.
+    let source = PeerId::new(signers[0].signer.public_key.clone());
+    let other_source = PeerId::new(signers[1].signer.public_key.clone());
+
+    let cache = Arc::new(Cache::new().with_limits(
+        clock.clock(),
+        PerSourceLimits {
+            window: time::Duration::seconds(60),
+            max_rejected_per_window: 0,
+            max_verified_bytes_per_window: usize::MAX,
+            max_tracked_sources: usize::MAX,
+        },
+    ));
+    cache.set_keys(e);
+
+    let mut a0 = signers[0].make_account_data(rng, now);
+    let mut a1_invalid = signers[1].make_account_data(rng, now);
+    *a1_invalid.signature_mut() = a0.signature_mut().clone();
+
+    // First batch from `source`: no prior rejections (0, right at the limit), so it goes
+    // through, and records 1 rejection (InvalidSignature).
+    let res = cache
+        .clone()
+        .insert_from(Some(source.clone()), vec![a0.clone(), a1_invalid.clone()])
+        .await;
+    assert_eq!(Some(Error::InvalidSignature), res.1);
+
+    // Second batch from the same source: the prior rejection already put it over
+    // max_rejected_per_window, so this is shed before it is even looked at.
+    let res = cache.clone().insert_from(Some(source.clone()), vec![a0.clone()]).await;
+    assert_eq!(Some(Error::RateLimited), res.1);
+    assert_eq!(0, res.0.len());
+
+    // A different source is unaffected by `source`'s rejections.
+    let res = cache.clone().insert_from(Some(other_source), vec![a0.clone()]).await;
+    assert_eq!(None, res.1);
+
+    // Advancing past the window rolls over and resets the shed source's budget.
+    clock.advance(time::Duration::seconds(61));
+    let res = cache.clone().insert_from(Some(source), vec![a0.clone()]).await;
+    assert_eq!(None, res.1);
+}
+
+#[tokio::test]
+async fn admission_map_evicts_idle_sources_once_over_cap() {
+    let mut rng = make_rng(2947294234);
+    let rng = &mut rng;
+    let clock = time::FakeClock::default();
+    let now = clock.now_utc();
+
+    // 3 distinct connection identities, standing in for 3 different peers; an attacker who
+    // can mint an unbounded number of these must not be able to grow the admission map
+    // without bound.
+    let signers = make_signers(rng, 3);
+    let e = make_account_keys(&signers);
+    let sources: Vec<PeerId> =
+        signers.iter().map(|s| PeerId::new(s.signer.public_key.clone())).collect();
+
+    let cache = Arc::new(Cache::new().with_limits(
+        clock.clock(),
+        PerSourceLimits {
+            window: time::Duration::seconds(60),
+            max_rejected_per_window: usize::MAX,
+            max_verified_bytes_per_window: usize::MAX,
+            max_tracked_sources: 2,
+        },
+    ));
+    cache.set_keys(e);
+
+    let a0 = signers[0].make_account_data(rng, now);
+
+    // Two sources fit under the cap with no eviction. The clock advances between them, so
+    // sources[0] has the older window_start.
+    for source in &sources[..2] {
+        let res = cache.clone().insert_from(Some(source.clone()), vec![a0.clone()]).await;
+        assert_eq!(None, res.1);
+        clock.advance(time::Duration::seconds(1));
+    }
+    assert_eq!(2, cache.admission.lock().len());
+
+    // A third, distinct source pushes the map over its cap. Since the prior two calls have
+    // long since returned, neither source's stats are reserved (idle), so the oldest one
+    // (sources[0]) is evicted to make room rather than the batch being shed.
+    clock.advance(time::Duration::seconds(1));
+    let res = cache.clone().insert_from(Some(sources[2].clone()), vec![a0.clone()]).await;
+    assert_eq!(None, res.1);
+    {
+        let admission = cache.admission.lock();
+        assert_eq!(2, admission.len());
+        assert!(!admission.contains_key(&sources[0]));
+        assert!(admission.contains_key(&sources[1]));
+    }
+
+    // sources[0]'s prior admission state is gone, so it is treated as a fresh source - not
+    // still over some leftover budget from before eviction.
+    let res = cache.clone().insert_from(Some(sources[0].clone()), vec![a0.clone()]).await;
+    assert_eq!(None, res.1);
 }
\ No newline at end of file